@@ -0,0 +1,386 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! At-rest encryption for sensitive portions of user metadata, borrowing
+//! crev-lib's `LockedId` design: a master passphrase is stretched into a
+//! symmetric key via Argon2 (the KDF's own params and salt are stored
+//! alongside the ciphertext, so they can change over the life of a
+//! deployment), and each sensitive field is sealed independently with a
+//! per-record nonce. A stolen metadata file is useless without the master
+//! secret. Locking and unlocking happens transparently when metadata is
+//! loaded from or saved to disk; anything already plaintext on disk is
+//! sealed the first time it's written back.
+
+use aes_gcm::aead::{Aead, KeyInit, OsRng as AeadOsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::{Argon2, Params};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use rand::RngCore;
+
+use crate::parseable::PARSEABLE;
+
+use super::user::{Basic, OAuth, PasswordVariant, PasswordVersion, User, UserInfo, UserType};
+
+/// Cost parameters for the metadata-at-rest KDF, analogous to crev-lib's
+/// `PassConfig`. Kept separate from the login [`super::user::HashPolicy`]
+/// so an operator tuning Argon2 cost for password hashing doesn't silently
+/// change the cost of sealing/unsealing metadata too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct KdfPolicy {
+    pub variant: PasswordVariant,
+    pub version: PasswordVersion,
+    /// Argon2 `t_cost`, the number of iterations.
+    pub time_cost: u32,
+    /// Argon2 `m_cost`, the memory size in KiB.
+    pub mem_cost: u32,
+    /// Argon2 `p_cost`, the degree of parallelism (lanes).
+    pub lanes: u32,
+}
+
+impl Default for KdfPolicy {
+    fn default() -> Self {
+        Self {
+            variant: PasswordVariant::Argon2id,
+            version: PasswordVersion::V0x13,
+            time_cost: Params::DEFAULT_T_COST,
+            mem_cost: Params::DEFAULT_M_COST,
+            lanes: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl KdfPolicy {
+    /// The KDF policy currently configured for this deployment.
+    pub fn current() -> Self {
+        PARSEABLE.options.metadata_kdf_policy
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        let params = Params::new(self.mem_cost, self.time_cost, self.lanes, None)
+            .expect("KDF policy params are valid");
+        Argon2::new(self.variant.into(), self.version.into(), params)
+    }
+}
+
+/// KDF parameters used to derive the metadata encryption key from the
+/// master secret. Stored alongside each sealed field so the KDF can be
+/// re-tuned without invalidating records sealed under older parameters.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct KdfParams {
+    #[serde(flatten)]
+    pub policy: KdfPolicy,
+    /// Base64-encoded KDF salt, freshly generated per sealed field.
+    pub salt: String,
+}
+
+/// A single sensitive value, encrypted at rest.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Sealed {
+    /// Base64-encoded AES-256-GCM ciphertext.
+    pub ciphertext: String,
+    /// Base64-encoded per-record nonce.
+    pub nonce: String,
+    pub kdf: KdfParams,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SealError {
+    #[error("sealed value is not valid base64: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("failed to decrypt sealed value, wrong master secret or corrupted record")]
+    Decrypt,
+    #[error("decrypted value is not valid utf-8: {0}")]
+    Utf8(#[from] std::string::FromUtf8Error),
+}
+
+fn master_secret() -> String {
+    PARSEABLE.options.metadata_master_secret.clone()
+}
+
+/// Re-derives the key for a single [`Sealed`] field. Runs a full Argon2
+/// pass every call; `seal_user`/`unseal_user` call this once per sensitive
+/// field, so sealing or unsealing a metadata store's whole user list costs
+/// one Argon2 KDF run per sensitive field per user. Known cost, not yet
+/// addressed: a process-lifetime cache keyed on `KdfParams` (policy + salt)
+/// would let records sharing a salt skip re-deriving the same key.
+fn derive_key(kdf: &KdfParams) -> Result<[u8; 32], SealError> {
+    let salt = BASE64.decode(&kdf.salt)?;
+    let mut key = [0u8; 32];
+    kdf.policy
+        .argon2()
+        .hash_password_into(master_secret().as_bytes(), &salt, &mut key)
+        .map_err(|_| SealError::Decrypt)?;
+    Ok(key)
+}
+
+/// Encrypts `plaintext` under the current [`KdfPolicy`] and a freshly
+/// derived key/nonce.
+pub fn seal(plaintext: &str) -> Sealed {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let kdf = KdfParams {
+        policy: KdfPolicy::current(),
+        salt: BASE64.encode(salt),
+    };
+
+    let key = derive_key(&kdf).expect("freshly generated salt always derives a key");
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+    let nonce = Aes256Gcm::generate_nonce(&mut AeadOsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext.as_bytes())
+        .expect("encryption with a fresh key/nonce cannot fail");
+
+    Sealed {
+        ciphertext: BASE64.encode(ciphertext),
+        nonce: BASE64.encode(nonce),
+        kdf,
+    }
+}
+
+/// Decrypts a [`Sealed`] value back to plaintext.
+pub fn unseal(sealed: &Sealed) -> Result<String, SealError> {
+    let key = derive_key(&sealed.kdf)?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+
+    let nonce_bytes = BASE64.decode(&sealed.nonce)?;
+    let ciphertext = BASE64.decode(&sealed.ciphertext)?;
+
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(&nonce_bytes), ciphertext.as_ref())
+        .map_err(|_| SealError::Decrypt)?;
+
+    Ok(String::from_utf8(plaintext)?)
+}
+
+/// `UserInfo` with `email` removed, so it can sit alongside a separately
+/// sealed `email` field without both claiming the same JSON key. Unknown
+/// fields are rejected so a legacy plaintext `user_info` that still carries
+/// an `email` falls through to the `LegacyUser` branch in `user.rs` instead
+/// of losing that email here.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SealedUserInfo {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    preferred_username: Option<String>,
+    #[serde(default)]
+    picture: Option<url::Url>,
+    #[serde(default)]
+    gender: Option<String>,
+    #[serde(default)]
+    updated_at: Option<i64>,
+    #[serde(default)]
+    groups: Option<Vec<String>>,
+}
+
+impl From<UserInfo> for SealedUserInfo {
+    fn from(info: UserInfo) -> Self {
+        Self {
+            name: info.name,
+            preferred_username: info.preferred_username,
+            picture: info.picture,
+            gender: info.gender,
+            updated_at: info.updated_at,
+            groups: info.groups,
+        }
+    }
+}
+
+impl SealedUserInfo {
+    fn into_user_info(self, email: Option<String>) -> UserInfo {
+        UserInfo {
+            name: self.name,
+            preferred_username: self.preferred_username,
+            picture: self.picture,
+            email,
+            gender: self.gender,
+            updated_at: self.updated_at,
+            groups: self.groups,
+        }
+    }
+}
+
+/// On-disk representation of a [`User`] with its sensitive fields sealed:
+/// the native password hash, and the OAuth profile email.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SealedUser {
+    #[serde(flatten)]
+    pub ty: SealedUserType,
+    pub roles: std::collections::HashSet<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum SealedUserType {
+    Native {
+        username: String,
+        password_hash: Sealed,
+    },
+    OAuth {
+        userid: String,
+        /// Nested, not flattened, to match the legacy (pre-encryption)
+        /// `OAuth` shape in `user.rs` — flattening these fields onto the
+        /// top level let a legacy nested record parse as a `SealedUser`
+        /// with every profile field silently defaulted to `None` instead
+        /// of falling through to the `LegacyUser` branch.
+        user_info: SealedUserInfo,
+        /// The real email; `user_info` never carries one in sealed form.
+        email: Option<Sealed>,
+    },
+}
+
+/// Seals `user`'s sensitive fields for storage. Idempotent: sealing a
+/// `User` whose fields were just unsealed from disk produces an
+/// equivalent-in-substance (freshly nonced) `SealedUser`.
+pub fn seal_user(user: &User) -> SealedUser {
+    let ty = match &user.ty {
+        UserType::Native(basic) => SealedUserType::Native {
+            username: basic.username.clone(),
+            password_hash: seal(&basic.password_hash),
+        },
+        UserType::OAuth(oauth) => {
+            let mut user_info = oauth.user_info.clone();
+            let email = user_info.email.take().map(|email| seal(&email));
+            SealedUserType::OAuth {
+                userid: oauth.userid.clone(),
+                user_info: user_info.into(),
+                email,
+            }
+        }
+    };
+
+    SealedUser {
+        ty,
+        roles: user.roles.clone(),
+    }
+}
+
+/// Unseals a [`SealedUser`] loaded from the metadata store back into a
+/// plaintext-in-memory [`User`].
+pub fn unseal_user(sealed: &SealedUser) -> Result<User, SealError> {
+    let ty = match &sealed.ty {
+        SealedUserType::Native {
+            username,
+            password_hash,
+        } => UserType::Native(Basic {
+            username: username.clone(),
+            password_hash: unseal(password_hash)?,
+        }),
+        SealedUserType::OAuth {
+            userid,
+            user_info,
+            email,
+        } => {
+            let email = email.as_ref().map(unseal).transpose()?;
+            UserType::OAuth(OAuth {
+                userid: userid.clone(),
+                user_info: user_info.clone().into_user_info(email),
+            })
+        }
+    };
+
+    Ok(User {
+        ty,
+        roles: sealed.roles.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn seal_unseal_round_trips_plaintext() {
+        let sealed = seal("hunter2");
+        assert_eq!(unseal(&sealed).unwrap(), "hunter2");
+    }
+
+    #[test]
+    fn unseal_fails_with_a_different_kdf_salt() {
+        let sealed = seal("hunter2");
+        let mut tampered = sealed.clone();
+        tampered.kdf.salt = BASE64.encode([0u8; 16]);
+        assert!(matches!(unseal(&tampered), Err(SealError::Decrypt)));
+    }
+
+    #[test]
+    fn seal_user_unseal_user_round_trips_native_user() {
+        let user = User {
+            ty: UserType::Native(Basic {
+                username: "alice".to_string(),
+                password_hash: "$argon2id$v=19$m=8,t=1,p=1$c29tZXNhbHQ$aGFzaA".to_string(),
+            }),
+            roles: ["admin".to_string()].into_iter().collect(),
+        };
+
+        let sealed = seal_user(&user);
+        let unsealed = unseal_user(&sealed).unwrap();
+        assert_eq!(unsealed, user);
+    }
+
+    #[test]
+    fn seal_user_unseal_user_round_trips_oauth_email() {
+        let user = User {
+            ty: UserType::OAuth(OAuth {
+                userid: "sub-123".to_string(),
+                user_info: UserInfo {
+                    name: Some("Alice".to_string()),
+                    preferred_username: None,
+                    picture: None,
+                    email: Some("alice@example.com".to_string()),
+                    gender: None,
+                    updated_at: None,
+                    groups: Some(vec!["eng".to_string()]),
+                },
+            }),
+            roles: HashSet::new(),
+        };
+
+        let sealed = seal_user(&user);
+        let unsealed = unseal_user(&sealed).unwrap();
+        assert_eq!(unsealed, user);
+    }
+
+    #[test]
+    fn legacy_nested_oauth_json_keeps_its_profile_fields() {
+        // Pre-encryption on-disk shape: `user_info` nested, no `email` key
+        // at all (it lived inside `user_info` before `UserInfo::email` was
+        // split out for sealing). Previously, flattening `SealedUserInfo`
+        // onto the top level meant none of these fields matched this
+        // nested JSON and all of them silently came back `None`.
+        let legacy = r#"{
+            "userid": "sub-123",
+            "user_info": {"name": "Alice", "groups": ["eng"]},
+            "roles": ["admin"]
+        }"#;
+
+        let user: User = serde_json::from_str(legacy).unwrap();
+        let UserType::OAuth(oauth) = user.ty else {
+            panic!("expected an OAuth user");
+        };
+
+        assert_eq!(oauth.userid, "sub-123");
+        assert_eq!(oauth.user_info.name.as_deref(), Some("Alice"));
+        assert_eq!(oauth.user_info.groups, Some(vec!["eng".to_string()]));
+        assert_eq!(user.roles, ["admin".to_string()].into_iter().collect());
+    }
+}
@@ -0,0 +1,238 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Configurable OIDC claim-to-role mapping, as rauthy and similar OIDC
+//! integrations do: at login, a named claim (by default `"groups"`) is read
+//! off the ID token and each value is mapped to zero or more Parseable
+//! roles. Runs on every login so IdP group changes re-sync a user's roles.
+
+use std::collections::HashSet;
+
+use crate::parseable::PARSEABLE;
+
+use super::user::MyClaims;
+
+/// How a single claim value is matched against a [`ClaimRoleRule`].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type", content = "value")]
+pub enum ClaimMatch {
+    /// The claim value must equal this string exactly.
+    Exact(String),
+    /// A prefix/wildcard pattern; a trailing `*` matches any suffix (e.g.
+    /// `"parseable-"` only matches itself, `"parseable-*"` matches
+    /// `"parseable-admin"`, `"parseable-viewer"`, ...).
+    Pattern(String),
+}
+
+impl ClaimMatch {
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            ClaimMatch::Exact(expected) => expected == value,
+            ClaimMatch::Pattern(pattern) => match pattern.strip_suffix('*') {
+                Some(prefix) => value.starts_with(prefix),
+                None => pattern == value,
+            },
+        }
+    }
+}
+
+/// Maps claim values matching `pattern` to `roles`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ClaimRoleRule {
+    #[serde(rename = "match")]
+    pub pattern: ClaimMatch,
+    pub roles: Vec<String>,
+}
+
+/// Operator-configured claim-to-role mapping, evaluated fresh on every
+/// login.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ClaimRoleMapping {
+    /// Which claim on the ID token to read; defaults to `"groups"` but is
+    /// operator-selectable for IdPs that surface membership under a
+    /// different name (e.g. `"roles"`).
+    pub claim: String,
+    pub rules: Vec<ClaimRoleRule>,
+    /// Assigned to authenticated users whose claim values matched no rule,
+    /// so an IdP user with no configured mapping isn't left with zero
+    /// roles.
+    pub default_role: Option<String>,
+}
+
+impl Default for ClaimRoleMapping {
+    fn default() -> Self {
+        Self {
+            claim: "groups".to_string(),
+            rules: Vec::new(),
+            default_role: None,
+        }
+    }
+}
+
+impl ClaimRoleMapping {
+    /// The mapping currently configured for this deployment.
+    pub fn current() -> Self {
+        PARSEABLE.options.oidc_role_mapping.clone()
+    }
+
+    /// Maps `claim_values` (e.g. IdP group names) to the Parseable roles
+    /// they grant, falling back to [`Self::default_role`] when nothing
+    /// matched.
+    pub fn resolve(&self, claim_values: &[String]) -> HashSet<String> {
+        let mut roles: HashSet<String> = claim_values
+            .iter()
+            .flat_map(|value| {
+                self.rules
+                    .iter()
+                    .filter(move |rule| rule.pattern.matches(value))
+                    .flat_map(|rule| rule.roles.iter().cloned())
+            })
+            .collect();
+
+        if roles.is_empty() {
+            roles.extend(self.default_role.clone());
+        }
+
+        roles
+    }
+}
+
+/// Reads the named claim's values off an ID token's claims. The well-known
+/// `"groups"` claim is surfaced directly via [`MyClaims::group`]; any other
+/// claim name is looked up among the token's additional claims.
+pub fn claim_values(claims: &MyClaims, claim_name: &str) -> Vec<String> {
+    if claim_name == "groups" {
+        return claims.group.clone().unwrap_or_default();
+    }
+
+    claims
+        .extra
+        .get(claim_name)
+        .and_then(|value| value.as_array())
+        .map(|values| {
+            values
+                .iter()
+                .filter_map(|value| value.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use openid::{Audiences, StandardClaims};
+    use serde_json::json;
+
+    use super::*;
+
+    fn claims_with_groups(groups: Vec<&str>) -> MyClaims {
+        MyClaims {
+            group: Some(groups.into_iter().map(str::to_string).collect()),
+            roles: Vec::new(),
+            standard_claims: StandardClaims::new(
+                "alice".to_string(),
+                Audiences::Single("https://parseable.test".to_string()),
+                "https://parseable.test".to_string(),
+                0,
+                0,
+            ),
+            extra: Default::default(),
+        }
+    }
+
+    #[test]
+    fn exact_match_requires_equality() {
+        let rule = ClaimMatch::Exact("parseable-admin".to_string());
+        assert!(rule.matches("parseable-admin"));
+        assert!(!rule.matches("parseable-viewer"));
+    }
+
+    #[test]
+    fn pattern_match_with_trailing_star_matches_any_suffix() {
+        let rule = ClaimMatch::Pattern("parseable-*".to_string());
+        assert!(rule.matches("parseable-admin"));
+        assert!(rule.matches("parseable-viewer"));
+        assert!(!rule.matches("other-admin"));
+    }
+
+    #[test]
+    fn pattern_match_without_a_star_requires_equality() {
+        let rule = ClaimMatch::Pattern("parseable-admin".to_string());
+        assert!(rule.matches("parseable-admin"));
+        assert!(!rule.matches("parseable-viewer"));
+    }
+
+    #[test]
+    fn resolve_maps_matching_claim_values_to_roles() {
+        let mapping = ClaimRoleMapping {
+            claim: "groups".to_string(),
+            rules: vec![ClaimRoleRule {
+                pattern: ClaimMatch::Pattern("parseable-*".to_string()),
+                roles: vec!["editor".to_string()],
+            }],
+            default_role: None,
+        };
+
+        let roles = mapping.resolve(&["parseable-editor".to_string(), "other".to_string()]);
+        assert_eq!(roles, ["editor".to_string()].into_iter().collect());
+    }
+
+    #[test]
+    fn resolve_falls_back_to_default_role_when_nothing_matched() {
+        let mapping = ClaimRoleMapping {
+            claim: "groups".to_string(),
+            rules: vec![ClaimRoleRule {
+                pattern: ClaimMatch::Exact("parseable-admin".to_string()),
+                roles: vec!["admin".to_string()],
+            }],
+            default_role: Some("viewer".to_string()),
+        };
+
+        let roles = mapping.resolve(&["unmapped-group".to_string()]);
+        assert_eq!(roles, ["viewer".to_string()].into_iter().collect());
+    }
+
+    #[test]
+    fn resolve_is_empty_with_no_match_and_no_default() {
+        let mapping = ClaimRoleMapping::default();
+        assert!(mapping.resolve(&["unmapped-group".to_string()]).is_empty());
+    }
+
+    #[test]
+    fn claim_values_reads_groups_directly_off_claims() {
+        let claims = claims_with_groups(vec!["eng", "ops"]);
+        assert_eq!(claim_values(&claims, "groups"), vec!["eng", "ops"]);
+    }
+
+    #[test]
+    fn claim_values_reads_a_custom_claim_from_extra() {
+        let mut claims = claims_with_groups(vec![]);
+        claims
+            .extra
+            .insert("department".to_string(), json!(["eng", "ops"]));
+
+        assert_eq!(claim_values(&claims, "department"), vec!["eng", "ops"]);
+    }
+
+    #[test]
+    fn claim_values_is_empty_for_an_unknown_claim() {
+        let claims = claims_with_groups(vec![]);
+        assert!(claim_values(&claims, "missing").is_empty());
+    }
+}
@@ -0,0 +1,358 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Role definitions and permission resolution, modeled on fabaccess's roles
+//! config: a role has a display name, an optional set of parents it
+//! inherits permissions from, and a list of dot-separated permission scopes
+//! that may end in a wildcard segment (e.g. `logstream.web.*`).
+
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+use once_cell::sync::Lazy;
+
+/// A named role: the permissions it grants directly, and the parent roles
+/// it inherits further permissions from.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RoleDef {
+    pub name: String,
+    pub display_name: String,
+    #[serde(default)]
+    pub parents: Vec<String>,
+    #[serde(default)]
+    pub permissions: Vec<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RoleError {
+    #[error("role {0:?} does not exist")]
+    NotFound(String),
+    #[error("role {0:?} already exists")]
+    AlreadyExists(String),
+    #[error("role {0:?} parent {1:?} does not exist")]
+    UnknownParent(String, String),
+    #[error("role {0:?} is part of a cyclic parent chain")]
+    Cycle(String),
+    #[error("role {0:?} is still a parent of {1:?}")]
+    InUse(String, String),
+}
+
+/// The server-wide registry of role definitions.
+pub static ROLES: Lazy<RwLock<HashMap<String, RoleDef>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Creates a role, rejecting it if the name is already taken, if any
+/// `parents` entry doesn't name an existing role, or if the resulting
+/// parent chain would be cyclic.
+pub fn create_role(def: RoleDef) -> Result<(), RoleError> {
+    create_role_in(&mut ROLES.write().unwrap(), def)
+}
+
+/// [`create_role`]'s implementation against an arbitrary map, so tests can
+/// exercise it against a throwaway map instead of the process-global
+/// [`ROLES`] (mirroring how `token.rs`'s `sign`/`verify` were split out of
+/// `issue_session_token`/`verify_session_token` to avoid depending on the
+/// `PARSEABLE` global).
+fn create_role_in(roles: &mut HashMap<String, RoleDef>, def: RoleDef) -> Result<(), RoleError> {
+    if roles.contains_key(&def.name) {
+        return Err(RoleError::AlreadyExists(def.name));
+    }
+    for parent in &def.parents {
+        if !roles.contains_key(parent) {
+            return Err(RoleError::UnknownParent(def.name.clone(), parent.clone()));
+        }
+    }
+
+    let name = def.name.clone();
+    roles.insert(name.clone(), def);
+    if let Err(err) = flatten_permissions(roles, &name, &mut HashSet::new()) {
+        roles.remove(&name);
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Replaces an existing role's definition in place, with the same
+/// parent-existence and cycle validation as [`create_role`]. Rejecting a
+/// cycle here means an operator who misconfigures a role's parents gets an
+/// error immediately, instead of [`has_permission`] silently degrading that
+/// role to granting nothing the next time someone holding it makes a
+/// request.
+pub fn update_role(def: RoleDef) -> Result<(), RoleError> {
+    update_role_in(&mut ROLES.write().unwrap(), def)
+}
+
+/// [`update_role`]'s implementation against an arbitrary map; see
+/// [`create_role_in`].
+fn update_role_in(roles: &mut HashMap<String, RoleDef>, def: RoleDef) -> Result<(), RoleError> {
+    let Some(previous) = roles.get(&def.name).cloned() else {
+        return Err(RoleError::NotFound(def.name));
+    };
+    for parent in &def.parents {
+        if !roles.contains_key(parent) {
+            return Err(RoleError::UnknownParent(def.name.clone(), parent.clone()));
+        }
+    }
+
+    let name = def.name.clone();
+    roles.insert(name.clone(), def);
+    if let Err(err) = flatten_permissions(roles, &name, &mut HashSet::new()) {
+        roles.insert(name, previous);
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Deletes a role, rejecting it if another role still lists it as a parent
+/// (deleting it anyway would leave that role's `parents` dangling, silently
+/// breaking permission resolution for every user holding it).
+pub fn delete_role(name: &str) -> Result<(), RoleError> {
+    delete_role_in(&mut ROLES.write().unwrap(), name)
+}
+
+/// [`delete_role`]'s implementation against an arbitrary map; see
+/// [`create_role_in`].
+fn delete_role_in(roles: &mut HashMap<String, RoleDef>, name: &str) -> Result<(), RoleError> {
+    if !roles.contains_key(name) {
+        return Err(RoleError::NotFound(name.to_string()));
+    }
+
+    if let Some(dependent) = roles
+        .values()
+        .find(|def| def.name != name && def.parents.iter().any(|parent| parent == name))
+    {
+        return Err(RoleError::InUse(name.to_string(), dependent.name.clone()));
+    }
+
+    roles.remove(name);
+    Ok(())
+}
+
+pub fn get_role(name: &str) -> Option<RoleDef> {
+    ROLES.read().unwrap().get(name).cloned()
+}
+
+pub fn list_roles() -> Vec<RoleDef> {
+    ROLES.read().unwrap().values().cloned().collect()
+}
+
+/// Transitively flattens `role` through its parents into the set of scopes
+/// it grants, rejecting cyclic parent chains.
+fn flatten_permissions(
+    roles: &HashMap<String, RoleDef>,
+    role: &str,
+    visiting: &mut HashSet<String>,
+) -> Result<HashSet<String>, RoleError> {
+    if !visiting.insert(role.to_string()) {
+        return Err(RoleError::Cycle(role.to_string()));
+    }
+
+    let def = roles
+        .get(role)
+        .ok_or_else(|| RoleError::NotFound(role.to_string()))?;
+
+    let mut permissions: HashSet<String> = def.permissions.iter().cloned().collect();
+    for parent in &def.parents {
+        permissions.extend(flatten_permissions(roles, parent, visiting)?);
+    }
+
+    visiting.remove(role);
+    Ok(permissions)
+}
+
+/// Whether any of `user_roles` (directly or via inherited parents) grants
+/// `scope`. Unknown roles or cyclic parent chains are treated as granting
+/// nothing rather than failing the whole check, since a single bad role
+/// shouldn't lock every user with that role out of everything.
+pub fn has_permission(user_roles: &HashSet<String>, scope: &str) -> bool {
+    let roles = ROLES.read().unwrap();
+    user_roles.iter().any(|role| {
+        let mut visiting = HashSet::new();
+        flatten_permissions(&roles, role, &mut visiting)
+            .map(|granted| granted.iter().any(|g| scope_matches(g, scope)))
+            .unwrap_or(false)
+    })
+}
+
+/// Matches a granted dot-separated scope against a requested one. A `*`
+/// segment matches any single segment; a trailing `*` also matches any
+/// number of remaining segments (e.g. `logstream.web.*` matches both
+/// `logstream.web.read` and `logstream.web.read.raw`).
+fn scope_matches(granted: &str, requested: &str) -> bool {
+    let granted: Vec<&str> = granted.split('.').collect();
+    let requested: Vec<&str> = requested.split('.').collect();
+
+    for (i, segment) in granted.iter().enumerate() {
+        if *segment == "*" && i == granted.len() - 1 {
+            return requested.len() >= granted.len();
+        }
+        match requested.get(i) {
+            Some(r) if segment == r || *segment == "*" => continue,
+            _ => return false,
+        }
+    }
+
+    requested.len() == granted.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_scope_matches_itself_only() {
+        assert!(scope_matches("logstream.web.read", "logstream.web.read"));
+        assert!(!scope_matches("logstream.web.read", "logstream.web.write"));
+        assert!(!scope_matches("logstream.web.read", "logstream.web"));
+    }
+
+    #[test]
+    fn mid_segment_wildcard_matches_a_single_segment() {
+        assert!(scope_matches("logstream.*.read", "logstream.web.read"));
+        assert!(!scope_matches("logstream.*.read", "logstream.web.write"));
+        assert!(!scope_matches("logstream.*.read", "logstream.web.raw.read"));
+    }
+
+    #[test]
+    fn trailing_wildcard_matches_any_number_of_remaining_segments() {
+        assert!(scope_matches("logstream.web.*", "logstream.web.read"));
+        assert!(scope_matches("logstream.web.*", "logstream.web.read.raw"));
+        assert!(!scope_matches("logstream.web.*", "logstream.ingest.read"));
+    }
+
+    #[test]
+    fn flatten_permissions_inherits_from_parents() {
+        let mut roles = HashMap::new();
+        roles.insert(
+            "child".to_string(),
+            RoleDef {
+                name: "child".to_string(),
+                display_name: "Child".to_string(),
+                parents: vec!["parent".to_string()],
+                permissions: vec!["logstream.web.read".to_string()],
+            },
+        );
+        roles.insert(
+            "parent".to_string(),
+            RoleDef {
+                name: "parent".to_string(),
+                display_name: "Parent".to_string(),
+                parents: vec![],
+                permissions: vec!["logstream.ingest.*".to_string()],
+            },
+        );
+
+        let mut visiting = HashSet::new();
+        let permissions = flatten_permissions(&roles, "child", &mut visiting).unwrap();
+        assert!(permissions.contains("logstream.web.read"));
+        assert!(permissions.contains("logstream.ingest.*"));
+    }
+
+    #[test]
+    fn flatten_permissions_rejects_cyclic_parents() {
+        let mut roles = HashMap::new();
+        roles.insert(
+            "a".to_string(),
+            RoleDef {
+                name: "a".to_string(),
+                display_name: "A".to_string(),
+                parents: vec!["b".to_string()],
+                permissions: vec![],
+            },
+        );
+        roles.insert(
+            "b".to_string(),
+            RoleDef {
+                name: "b".to_string(),
+                display_name: "B".to_string(),
+                parents: vec!["a".to_string()],
+                permissions: vec![],
+            },
+        );
+
+        let mut visiting = HashSet::new();
+        let err = flatten_permissions(&roles, "a", &mut visiting).unwrap_err();
+        assert!(matches!(err, RoleError::Cycle(role) if role == "a"));
+    }
+
+    #[test]
+    fn update_role_rejects_a_parent_cycle() {
+        let mut roles = HashMap::new();
+
+        create_role_in(
+            &mut roles,
+            RoleDef {
+                name: "a".to_string(),
+                display_name: "A".to_string(),
+                parents: vec![],
+                permissions: vec!["logstream.web.read".to_string()],
+            },
+        )
+        .unwrap();
+        create_role_in(
+            &mut roles,
+            RoleDef {
+                name: "b".to_string(),
+                display_name: "B".to_string(),
+                parents: vec!["a".to_string()],
+                permissions: vec![],
+            },
+        )
+        .unwrap();
+
+        let err = update_role_in(
+            &mut roles,
+            RoleDef {
+                name: "a".to_string(),
+                display_name: "A".to_string(),
+                parents: vec!["b".to_string()],
+                permissions: vec!["logstream.web.read".to_string()],
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(err, RoleError::Cycle(role) if role == "a"));
+
+        // the rejected update must not have clobbered the prior definition
+        assert!(roles.get("a").unwrap().parents.is_empty());
+    }
+
+    #[test]
+    fn delete_role_rejects_role_still_used_as_a_parent() {
+        let mut roles = HashMap::new();
+        roles.insert(
+            "parent".to_string(),
+            RoleDef {
+                name: "parent".to_string(),
+                display_name: "Parent".to_string(),
+                parents: vec![],
+                permissions: vec![],
+            },
+        );
+        roles.insert(
+            "child".to_string(),
+            RoleDef {
+                name: "child".to_string(),
+                display_name: "Child".to_string(),
+                parents: vec!["parent".to_string()],
+                permissions: vec![],
+            },
+        );
+
+        let err = delete_role_in(&mut roles, "parent").unwrap_err();
+        assert!(matches!(err, RoleError::InUse(name, dependent) if name == "parent" && dependent == "child"));
+    }
+}
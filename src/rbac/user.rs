@@ -19,8 +19,8 @@
 use std::collections::HashSet;
 
 use argon2::{
-    password_hash::{rand_core::OsRng, PasswordHasher, SaltString},
-    Argon2, PasswordHash, PasswordVerifier,
+    password_hash::{rand_core::OsRng, PasswordHasher, PasswordVerifier as _, SaltString},
+    Algorithm, Argon2, Params, PasswordHash, Version,
 };
 
 use openid::{CompactJson, CustomClaims, StandardClaims, StandardClaimsSubject};
@@ -35,13 +35,52 @@ pub enum UserType {
     OAuth(OAuth),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct User {
-    #[serde(flatten)]
     pub ty: UserType,
     pub roles: HashSet<String>,
 }
 
+// `User` is sealed/unsealed transparently on save/load rather than
+// #[derive]d: serializing always seals (see `super::crypto::seal_user`),
+// and deserializing tries the sealed shape first, falling back to the
+// legacy plaintext one so records written before encryption-at-rest keep
+// loading — they're sealed for real the next time this user is saved.
+impl serde::Serialize for User {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        super::crypto::seal_user(self).serialize(serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for User {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let value = serde_json::Value::deserialize(deserializer)?;
+
+        if let Ok(sealed) = serde_json::from_value::<super::crypto::SealedUser>(value.clone()) {
+            return super::crypto::unseal_user(&sealed).map_err(serde::de::Error::custom);
+        }
+
+        #[derive(serde::Deserialize)]
+        struct LegacyUser {
+            #[serde(flatten)]
+            ty: UserType,
+            roles: HashSet<String>,
+        }
+
+        let legacy: LegacyUser = serde_json::from_value(value).map_err(serde::de::Error::custom)?;
+        Ok(User {
+            ty: legacy.ty,
+            roles: legacy.roles,
+        })
+    }
+}
+
 impl User {
     // create a new User and return self with password generated for said user.
     pub fn new_basic(username: String) -> (Self, String) {
@@ -58,7 +97,14 @@ impl User {
         )
     }
 
-    pub fn new_oauth(username: String, roles: HashSet<String>, user_info: UserInfo) -> Self {
+    /// Builds an OAuth user, deriving its roles from `claims` via the
+    /// configured [`super::oidc::ClaimRoleMapping`]. Run on every login so
+    /// that changes to the user's IdP group membership re-sync their roles.
+    pub fn new_oauth(username: String, claims: &MyClaims, user_info: UserInfo) -> Self {
+        let mapping = super::oidc::ClaimRoleMapping::current();
+        let values = super::oidc::claim_values(claims, &mapping.claim);
+        let roles = mapping.resolve(&values);
+
         Self {
             ty: UserType::OAuth(OAuth {
                 userid: user_info.name.clone().unwrap_or(username),
@@ -85,6 +131,14 @@ impl User {
     pub fn roles(&self) -> Vec<String> {
         self.roles.iter().cloned().collect()
     }
+
+    /// Whether this user's roles, transitively flattened through their
+    /// parents, grant `scope` (e.g. `"logstream.web.read"`). See
+    /// [`super::role::has_permission`] for how parent inheritance and
+    /// wildcard scopes are resolved.
+    pub fn has_permission(&self, scope: &str) -> bool {
+        super::role::has_permission(&self.roles, scope)
+    }
 }
 
 // Represents a User in the system
@@ -104,8 +158,81 @@ impl Basic {
         PassCode { password, hash }
     }
 
-    pub fn verify_password(&self, password: &str) -> bool {
-        verify(&self.password_hash, password)
+    /// Verify `password` against the stored hash. On a successful match
+    /// against a hash whose encoded params are weaker than the currently
+    /// configured [`HashPolicy`] (or that uses a different/legacy algorithm
+    /// or pepper key), `rehash` carries a freshly computed PHC string hashed
+    /// with the current policy and pepper. Callers should persist `rehash`
+    /// back to the user's metadata; the migration is transparent to the
+    /// user and idempotent (re-verifying the new hash reports no further
+    /// staleness).
+    pub fn verify_password(&self, password: &str) -> PasswordVerifyOutcome {
+        verify_hash(&self.password_hash, password)
+    }
+}
+
+/// Shared pepper-lookup + policy + Argon2-verify implementation behind both
+/// [`Basic::verify_password`] and the free-standing [`verify`], so the two
+/// entry points can't drift out of sync with future pepper/policy changes.
+fn verify_hash(password_hash: &str, password: &str) -> PasswordVerifyOutcome {
+    let (key_id, phc) = split_stored_hash(password_hash);
+
+    let Ok(parsed_hash) = PasswordHash::new(&phc) else {
+        return PasswordVerifyOutcome::Invalid;
+    };
+
+    // A hash keyed under a pepper we no longer hold (neither current
+    // nor retained as a previous key) can never be verified again.
+    let pepper = match key_id {
+        Some(id) => match Pepper::find(id) {
+            Some(pepper) => Some(pepper),
+            None => return PasswordVerifyOutcome::Invalid,
+        },
+        None => None,
+    };
+
+    if HashPolicy::current()
+        .argon2(pepper.as_ref())
+        .verify_password(password.as_bytes(), &parsed_hash)
+        .is_err()
+    {
+        return PasswordVerifyOutcome::Invalid;
+    }
+
+    let current_key_id = Pepper::current().map(|pepper| pepper.key_id);
+    let stale = HashPolicy::current().is_stale(&parsed_hash) || key_id != current_key_id.as_deref();
+    let rehash = stale.then(|| gen_hash(password));
+
+    PasswordVerifyOutcome::Valid { rehash }
+}
+
+/// Splits a stored `password_hash` into an optional pepper key-id and the
+/// PHC string it prefixes. Hashes minted before peppering was enabled (or
+/// while no pepper is configured) are bare PHC strings and start with `$`;
+/// peppered hashes are stored as `<key_id>$<phc>`.
+fn split_stored_hash(stored: &str) -> (Option<&str>, String) {
+    if stored.starts_with('$') {
+        return (None, stored.to_string());
+    }
+    match stored.split_once('$') {
+        Some((key_id, rest)) => (Some(key_id), format!("${rest}")),
+        None => (None, stored.to_string()),
+    }
+}
+
+/// Outcome of verifying a plaintext password against a stored PHC hash.
+pub enum PasswordVerifyOutcome {
+    /// The password did not match the stored hash.
+    Invalid,
+    /// The password matched. `rehash` is `Some` when the stored hash should
+    /// be replaced because its params predate the current [`HashPolicy`] or
+    /// it was keyed under a pepper other than the current one.
+    Valid { rehash: Option<String> },
+}
+
+impl PasswordVerifyOutcome {
+    pub fn is_valid(&self) -> bool {
+        matches!(self, PasswordVerifyOutcome::Valid { .. })
     }
 }
 
@@ -113,23 +240,25 @@ impl Basic {
 // $<id>[$v=<version>][$<param>=<value>(,<param>=<value>)*][$<salt>[$<hash>]])
 // ref https://github.com/P-H-C/phc-string-format/blob/master/phc-sf-spec.md#specification
 pub fn verify(password_hash: &str, password: &str) -> bool {
-    let parsed_hash = PasswordHash::new(password_hash).unwrap();
-    Argon2::default()
-        .verify_password(password.as_bytes(), &parsed_hash)
-        .is_ok()
+    verify_hash(password_hash, password).is_valid()
 }
 
 // generate a one way hash for password to be stored in metadata file
 // ref https://github.com/P-H-C/phc-string-format/blob/master/phc-sf-spec.md
 fn gen_hash(password: &str) -> String {
+    let policy = HashPolicy::current();
+    let pepper = Pepper::current();
     let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    let hashcode = argon2
+    let phc = policy
+        .argon2(pepper.as_ref())
         .hash_password(password.as_bytes(), &salt)
         .expect("can hash random alphanumeric")
         .to_string();
 
-    hashcode
+    match pepper {
+        Some(pepper) => format!("{}{phc}", pepper.key_id),
+        None => phc,
+    }
 }
 
 pub struct PassCode {
@@ -137,6 +266,178 @@ pub struct PassCode {
     pub hash: String,
 }
 
+/// Tunable Argon2 cost parameters for password hashing, analogous to the
+/// `PassConfig` used by crev-lib. All hashes already carry their own
+/// parameters in the PHC string, so a stored hash can be checked against the
+/// currently configured policy without any side table: if the encoded
+/// params are weaker than `self`, or the hash uses a different algorithm
+/// entirely, it's considered stale and due for a rehash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct HashPolicy {
+    pub variant: PasswordVariant,
+    pub version: PasswordVersion,
+    /// Argon2 `t_cost`, the number of iterations.
+    pub time_cost: u32,
+    /// Argon2 `m_cost`, the memory size in KiB.
+    pub mem_cost: u32,
+    /// Argon2 `p_cost`, the degree of parallelism (lanes).
+    pub lanes: u32,
+}
+
+impl Default for HashPolicy {
+    fn default() -> Self {
+        Self {
+            variant: PasswordVariant::Argon2id,
+            version: PasswordVersion::V0x13,
+            time_cost: Params::DEFAULT_T_COST,
+            mem_cost: Params::DEFAULT_M_COST,
+            lanes: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl HashPolicy {
+    /// The policy currently configured for this deployment.
+    pub fn current() -> Self {
+        PARSEABLE.options.hash_policy
+    }
+
+    fn params(&self) -> Params {
+        Params::new(self.mem_cost, self.time_cost, self.lanes, None)
+            .expect("hashing policy params are valid")
+    }
+
+    /// Builds an `Argon2` instance for this policy, keyed with `pepper`'s
+    /// secret when one is given (the `Argon2::new_with_secret` constructor),
+    /// so a leaked metadata file alone can't be brute-forced offline
+    /// without also stealing the pepper.
+    pub(crate) fn argon2<'k>(&self, pepper: Option<&'k Pepper>) -> Argon2<'k> {
+        match pepper {
+            Some(pepper) => Argon2::new_with_secret(
+                &pepper.secret,
+                self.variant.into(),
+                self.version.into(),
+                self.params(),
+            )
+            .expect("pepper produces a valid keyed Argon2 instance"),
+            None => Argon2::new(self.variant.into(), self.version.into(), self.params()),
+        }
+    }
+
+    /// Whether `hash`'s encoded algorithm/params are weaker than this
+    /// policy, meaning it should be rehashed on the next successful login.
+    fn is_stale(&self, hash: &PasswordHash) -> bool {
+        let Some(params) = hash.params.iter().map(|(k, v)| (k, v.to_string())).fold(
+            Some((None, None, None)),
+            |acc, (k, v)| {
+                let (mut m, mut t, mut p) = acc?;
+                match k {
+                    "m" => m = v.parse::<u32>().ok(),
+                    "t" => t = v.parse::<u32>().ok(),
+                    "p" => p = v.parse::<u32>().ok(),
+                    _ => {}
+                }
+                Some((m, t, p))
+            },
+        ) else {
+            return true;
+        };
+
+        let (Some(m), Some(t), Some(p)) = params else {
+            return true;
+        };
+
+        if hash.algorithm.as_str() != self.variant.as_str() {
+            return true;
+        }
+
+        if let Some(version) = hash.version {
+            if version != self.version as u32 {
+                return true;
+            }
+        }
+
+        m < self.mem_cost || t < self.time_cost || p < self.lanes
+    }
+}
+
+/// Serializable mirror of [`argon2::Algorithm`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum PasswordVariant {
+    Argon2d,
+    Argon2i,
+    Argon2id,
+}
+
+impl From<PasswordVariant> for Algorithm {
+    fn from(variant: PasswordVariant) -> Self {
+        match variant {
+            PasswordVariant::Argon2d => Algorithm::Argon2d,
+            PasswordVariant::Argon2i => Algorithm::Argon2i,
+            PasswordVariant::Argon2id => Algorithm::Argon2id,
+        }
+    }
+}
+
+impl PasswordVariant {
+    fn as_str(&self) -> &'static str {
+        Algorithm::from(*self).as_str()
+    }
+}
+
+/// Serializable mirror of [`argon2::Version`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum PasswordVersion {
+    V0x10 = 0x10,
+    V0x13 = 0x13,
+}
+
+impl From<PasswordVersion> for Version {
+    fn from(version: PasswordVersion) -> Self {
+        match version {
+            PasswordVersion::V0x10 => Version::V0x10,
+            PasswordVersion::V0x13 => Version::V0x13,
+        }
+    }
+}
+
+/// A server-wide secret mixed into Argon2 as its keyed secret (libpasta's
+/// "alternate key source"/HMAC approach), loaded once at startup from an env
+/// var or file. `key_id` is a short, non-secret identifier stored alongside
+/// the PHC string so that rotating the pepper can coexist with hashes keyed
+/// under a previous pepper during a migration window: on successful verify
+/// against an old `key_id`, the caller rehashes with the current pepper,
+/// pairing naturally with the [`HashPolicy`] rehash-on-login path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pepper {
+    pub key_id: String,
+    pub secret: Vec<u8>,
+}
+
+impl Pepper {
+    /// The pepper new hashes are minted with, if peppering is configured
+    /// for this deployment.
+    pub fn current() -> Option<Self> {
+        PARSEABLE.options.pepper.clone()
+    }
+
+    /// Peppers retired by rotation but still accepted for verification so
+    /// existing hashes keep working until they're rehashed.
+    pub fn previous() -> Vec<Self> {
+        PARSEABLE.options.previous_peppers.clone()
+    }
+
+    /// Looks up the pepper matching a stored hash's `key_id`, checking the
+    /// current pepper before falling back to retired ones.
+    fn find(key_id: &str) -> Option<Self> {
+        Self::current()
+            .into_iter()
+            .chain(Self::previous())
+            .find(|pepper| pepper.key_id == key_id)
+    }
+}
+
 pub fn get_admin_user() -> User {
     let username = PARSEABLE.options.username.clone();
     let password = PARSEABLE.options.password.clone();
@@ -179,8 +480,19 @@ pub struct UserInfo {
 #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct MyClaims {
     pub group: Option<Vec<String>>,
+    /// The user's roles at the time the session token was issued. Carried
+    /// in the token so request authorization doesn't need to hit the
+    /// metadata store on every call; re-minted on the next login if roles
+    /// change in the meantime.
+    #[serde(default)]
+    pub roles: Vec<String>,
     #[serde(flatten)]
     pub standard_claims: StandardClaims,
+    /// Claims beyond `group` and the standard set, so an operator-configured
+    /// claim-to-role mapping (see [`super::oidc`]) can read an IdP-specific
+    /// claim name without this type needing to know it up front.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 impl CustomClaims for MyClaims {
@@ -191,12 +503,28 @@ impl CustomClaims for MyClaims {
 
 impl StandardClaimsSubject for MyClaims {
     fn sub(&self) -> Result<&str, openid::error::StandardClaimsSubjectMissing> {
-        todo!()
+        Ok(&self.standard_claims.sub)
     }
 }
 
 impl CompactJson for MyClaims {}
 
+impl UserInfo {
+    /// Builds `UserInfo` from the OIDC userinfo response, populating
+    /// `groups` from the same configured claim used for role mapping (see
+    /// [`super::oidc::ClaimRoleMapping`]) so group membership stays visible
+    /// without re-deriving it from the resolved roles.
+    pub fn from_userinfo_and_claims(user: openid::Userinfo, claims: &MyClaims) -> Self {
+        let mapping = super::oidc::ClaimRoleMapping::current();
+        let groups = super::oidc::claim_values(claims, &mapping.claim);
+
+        UserInfo {
+            groups: (!groups.is_empty()).then_some(groups),
+            ..user.into()
+        }
+    }
+}
+
 impl From<openid::Userinfo> for UserInfo {
     fn from(user: openid::Userinfo) -> Self {
         UserInfo {
@@ -218,3 +546,75 @@ impl From<openid::Userinfo> for UserInfo {
     }
 }
  */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weak_policy() -> HashPolicy {
+        HashPolicy {
+            variant: PasswordVariant::Argon2id,
+            version: PasswordVersion::V0x13,
+            time_cost: 1,
+            mem_cost: 8,
+            lanes: 1,
+        }
+    }
+
+    fn phc_under(policy: &HashPolicy, password: &str) -> String {
+        let salt = SaltString::generate(&mut OsRng);
+        policy
+            .argon2(None)
+            .hash_password(password.as_bytes(), &salt)
+            .expect("valid policy hashes")
+            .to_string()
+    }
+
+    #[test]
+    fn hash_is_not_stale_against_the_policy_it_was_hashed_under() {
+        let policy = weak_policy();
+        let phc = phc_under(&policy, "hunter2");
+        let parsed = PasswordHash::new(&phc).unwrap();
+
+        assert!(!policy.is_stale(&parsed));
+    }
+
+    #[test]
+    fn hash_is_stale_once_policy_cost_increases() {
+        let weak = weak_policy();
+        let phc = phc_under(&weak, "hunter2");
+        let parsed = PasswordHash::new(&phc).unwrap();
+
+        let stronger = HashPolicy {
+            time_cost: weak.time_cost + 1,
+            ..weak
+        };
+        assert!(stronger.is_stale(&parsed));
+    }
+
+    #[test]
+    fn hash_is_stale_when_algorithm_differs() {
+        let argon2i = HashPolicy {
+            variant: PasswordVariant::Argon2i,
+            ..weak_policy()
+        };
+        let phc = phc_under(&argon2i, "hunter2");
+        let parsed = PasswordHash::new(&phc).unwrap();
+
+        assert!(weak_policy().is_stale(&parsed));
+    }
+
+    #[test]
+    fn splits_legacy_hash_with_no_key_id() {
+        let (key_id, phc) = split_stored_hash("$argon2id$v=19$m=8,t=1,p=1$c29tZXNhbHQ$aGFzaA");
+        assert_eq!(key_id, None);
+        assert_eq!(phc, "$argon2id$v=19$m=8,t=1,p=1$c29tZXNhbHQ$aGFzaA");
+    }
+
+    #[test]
+    fn splits_peppered_hash_with_key_id() {
+        let (key_id, phc) = split_stored_hash("v2$argon2id$v=19$m=8,t=1,p=1$c29tZXNhbHQ$aGFzaA");
+        assert_eq!(key_id, Some("v2"));
+        assert_eq!(phc, "$argon2id$v=19$m=8,t=1,p=1$c29tZXNhbHQ$aGFzaA");
+    }
+}